@@ -2,17 +2,36 @@ use anyhow::Result;
 use log::{debug, error, info};
 use osmpbfreader::objects::{Node, Way};
 use osmpbfreader::{OsmObj, OsmPbfReader};
-use rusqlite::{params, Connection};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::params;
+use serde::Deserialize;
 use std::f64::consts::PI;
 use std::fs::File;
 use std::hash::{Hash, Hasher};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Database {
-    conn: Connection,
+    pool: Pool<SqliteConnectionManager>,
     filename: String,
 }
 
+/// Which way along a waterway's direction (as encoded by OSM way node order)
+/// a route is allowed to travel.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteMode {
+    Downstream,
+    Upstream,
+    Both,
+}
+
+impl Default for RouteMode {
+    fn default() -> Self {
+        RouteMode::Both
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RouteNode {
     id: i64,
@@ -39,10 +58,32 @@ impl RouteNode {
     }
 }
 
+/// Types that can be extracted from a single `rusqlite` result row.
+trait FromRow: Sized {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self>;
+}
+
+impl FromRow for RouteNode {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(RouteNode {
+            id: row.get(0)?,
+            lat: row.get(1)?,
+            lon: row.get(2)?,
+        })
+    }
+}
+
+fn row_extract<T: FromRow>(row: &rusqlite::Row) -> rusqlite::Result<T> {
+    T::from_row(row)
+}
+
 impl Database {
     pub fn new(filename: &str) -> Result<Database> {
+        let manager = SqliteConnectionManager::file(filename).with_init(|conn| {
+            conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA busy_timeout = 5000;")
+        });
         let db = Database {
-            conn: Connection::open(filename)?,
+            pool: Pool::new(manager)?,
             filename: filename.into(),
         };
         db.init()?;
@@ -52,16 +93,17 @@ impl Database {
 
     fn init(&self) -> Result<()> {
         debug!("Initializing database");
-        self.conn.execute(
+        let conn = self.pool.get()?;
+        conn.execute(
             "CREATE TABLE IF NOT EXISTS nodes ( \
             id INTEGER PRIMARY KEY, \
-            lat DOUBLE, \
-            lon DOUBLE, \
+            lat DOUBLE NOT NULL, \
+            lon DOUBLE NOT NULL, \
             quadkey CHARACTER(10) \
             )",
             params![],
         )?;
-        self.conn.execute(
+        conn.execute(
             "CREATE TABLE IF NOT EXISTS links ( \
             id INTEGER PRIMARY KEY AUTOINCREMENT, \
             source INTEGER, \
@@ -71,11 +113,11 @@ impl Database {
             )",
             params![],
         )?;
-        self.conn.execute(
+        conn.execute(
             "CREATE INDEX IF NOT EXISTS links_source ON links(source)",
             params![],
         )?;
-        self.conn.execute(
+        conn.execute(
             "CREATE INDEX IF NOT EXISTS links_destination ON links(destination)",
             params![],
         )?;
@@ -85,9 +127,8 @@ impl Database {
 
     fn fixup_quadkeys(&self) -> Result<()> {
         info!("Fixing quadkeys...");
-        let mut stmt = self
-            .conn
-            .prepare("SELECT id, lat, lon FROM nodes WHERE quadkey IS NULL")?;
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare("SELECT id, lat, lon FROM nodes WHERE quadkey IS NULL")?;
         let nodes_iter = stmt.query_map(params![], |row| {
             let id: i64 = row.get(0)?;
             let lat: f64 = row.get(1)?;
@@ -96,9 +137,7 @@ impl Database {
 
             Ok((id, quadkey))
         });
-        let mut stmt2 = self
-            .conn
-            .prepare("UPDATE nodes SET quadkey = ? WHERE id = ?")?;
+        let mut stmt2 = conn.prepare("UPDATE nodes SET quadkey = ? WHERE id = ?")?;
         let mut count = 0;
         for res in nodes_iter? {
             if let Ok((id, quadkey)) = res {
@@ -114,16 +153,16 @@ impl Database {
     }
 
     pub fn node_count(&self) -> Result<i64> {
-        let count: i64 = self
-            .conn
-            .query_row("SELECT count(*) FROM nodes", params![], |row| row.get(0))?;
+        let conn = self.pool.get()?;
+        let count: i64 =
+            conn.query_row("SELECT count(*) FROM nodes", params![], |row| row.get(0))?;
         Ok(count)
     }
 
     pub fn link_count(&self) -> Result<i64> {
-        let count: i64 = self
-            .conn
-            .query_row("SELECT count(*) FROM links", params![], |row| row.get(0))?;
+        let conn = self.pool.get()?;
+        let count: i64 =
+            conn.query_row("SELECT count(*) FROM links", params![], |row| row.get(0))?;
         Ok(count)
     }
 
@@ -143,26 +182,20 @@ impl Database {
                 .any(|v| v == "river" || v == "stream" || v == "canal")
     }
 
-    fn update_node(&self, node: &Node) -> Result<()> {
+    fn update_node(stmt: &mut rusqlite::Statement, node: &Node) -> Result<()> {
         debug!("Updating node {:?}", node);
-        let quadkey = super::quadkey::Quadkey::new(node.lat(), node.lon(), 13);
-        self.conn.execute(
-            "INSERT OR REPLACE INTO nodes (id, lat, lon, quadkey) VALUES (?, ?, ?, ?)",
-            params![node.id.0, node.lat(), node.lon(), quadkey.to_string()],
-        )?;
-
+        // Quadkeys are left NULL here and filled in bulk by fixup_quadkeys()
+        // once the whole import has committed.
+        stmt.execute(params![node.id.0, node.lat(), node.lon()])?;
         Ok(())
     }
 
-    fn update_way(&self, way: &Way) -> Result<()> {
+    fn update_way(stmt: &mut rusqlite::Statement, way: &Way) -> Result<()> {
         debug!("Updating way {:?} {:?}", way.id, way.tags);
 
         for node_pair in way.nodes.windows(2) {
             debug!("Inserting link between {:?}", node_pair);
-            self.conn.execute(
-                "INSERT OR REPLACE INTO links (source, destination) VALUES (?, ?)",
-                params![node_pair[0].0, node_pair[1].0],
-            )?;
+            stmt.execute(params![node_pair[0].0, node_pair[1].0])?;
         }
 
         Ok(())
@@ -176,18 +209,45 @@ impl Database {
         let objs = pbf.get_objs_and_deps(|x| self.filter_object(x))?;
 
         info!("Updating database with {:?} objects...", objs.len());
-        let mut count = 0;
-        for (_, obj) in &objs {
-            match obj {
-                OsmObj::Node(node) => self.update_node(node)?,
-                OsmObj::Way(way) => self.update_way(way)?,
-                _ => (),
-            }
-            if count % 10000 == 0 {
-                info!("Updated {:?} objects so far", count);
+        let started = std::time::Instant::now();
+
+        // Import on a dedicated, non-pooled connection so the relaxed
+        // durability pragmas below never leak into a connection that's
+        // later checked out of `self.pool` to serve a read.
+        let mut conn = rusqlite::Connection::open(&self.filename)?;
+        conn.execute_batch("PRAGMA synchronous = OFF; PRAGMA journal_mode = MEMORY;")?;
+
+        let mut count: u64 = 0;
+        let tx = conn.transaction()?;
+        {
+            let mut insert_node =
+                tx.prepare("INSERT OR REPLACE INTO nodes (id, lat, lon) VALUES (?, ?, ?)")?;
+            let mut insert_link = tx
+                .prepare("INSERT OR REPLACE INTO links (source, destination) VALUES (?, ?)")?;
+
+            for (_, obj) in &objs {
+                match obj {
+                    OsmObj::Node(node) => Self::update_node(&mut insert_node, node)?,
+                    OsmObj::Way(way) => Self::update_way(&mut insert_link, way)?,
+                    _ => (),
+                }
+                if count % 10000 == 0 {
+                    info!("Updated {:?} objects so far", count);
+                }
+                count += 1;
             }
-            count += 1;
         }
+        tx.commit()?;
+
+        let elapsed = started.elapsed();
+        info!(
+            "Imported {:?} objects in {:.2?} ({:.0} objects/sec)",
+            count,
+            elapsed,
+            count as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+        );
+
+        self.fixup_quadkeys()?;
 
         Ok(())
     }
@@ -200,28 +260,54 @@ impl Database {
     }
 
     fn find_near_err(&self, lat: f64, lon: f64) -> Result<RouteNode> {
-        // compute quadkey, find all nodes near, sort by distance, pick first
+        // compute the 3x3 block of quadtiles around the query point, find all
+        // nodes near, sort by distance, pick first. A single tile isn't enough:
+        // a coordinate near a tile border can be genuinely closest to a node
+        // that lives one tile over.
         debug!("Looking for node near {:?} {:?}", lat, lon);
-        let quadkey = super::quadkey::Quadkey::new(lat, lon, 12);
-        debug!("Quadkey: {:?}", quadkey);
-        let mut stmt = self
-            .conn
-            .prepare("SELECT id, lat, lon FROM nodes WHERE substr(quadkey, 1, ?) = ?")?;
-        let nodes_iter = stmt
-            .query_map(params![12, quadkey.to_string()], |row| {
-                Ok(RouteNode {
-                    id: row.get(0)?,
-                    lat: row.get(1)?,
-                    lon: row.get(2)?,
-                })
-            })?
-            .map(|n| n.unwrap());
+        let zoom: u8 = 12;
+        let (xtile, ytile) = super::quadkey::Quadkey::tile(lat, lon, zoom);
+        let tiles_per_axis = 1i64 << zoom;
+
+        let mut quadkeys: Vec<String> = Vec::new();
+        for dx in -1i64..=1 {
+            for dy in -1i64..=1 {
+                let x = (xtile as i64 + dx).rem_euclid(tiles_per_axis) as u32;
+                let y = ytile as i64 + dy;
+                if y < 0 || y >= tiles_per_axis {
+                    continue;
+                }
+                let quadkey = super::quadkey::Quadkey::from_tile(x, y as u32, zoom);
+                quadkeys.push(quadkey.to_string().clone());
+            }
+        }
+        quadkeys.sort();
+        quadkeys.dedup();
+        debug!("Candidate quadkeys: {:?}", quadkeys);
+
+        let conn = self.pool.get()?;
+        let placeholders = quadkeys.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
+        let sql = format!(
+            "SELECT id, lat, lon FROM nodes WHERE substr(quadkey, 1, ?) IN ({})",
+            placeholders
+        );
+        let mut stmt = conn.prepare(&sql)?;
+        let zoom_len = zoom as i64;
+        let mut bind_values: Vec<&dyn rusqlite::ToSql> = Vec::with_capacity(quadkeys.len() + 1);
+        bind_values.push(&zoom_len);
+        for quadkey in &quadkeys {
+            bind_values.push(quadkey);
+        }
+        let nodes: Vec<RouteNode> = stmt
+            .query_map(rusqlite::params_from_iter(bind_values), row_extract::<RouteNode>)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
         let goal = RouteNode {
             id: 0,
             lat: lat,
             lon: lon,
         };
-        let min_node = nodes_iter.min_by(|a, b| {
+        let min_node = nodes.into_iter().min_by(|a, b| {
             let dist_a = distance_between(a, &goal);
             let dist_b = distance_between(b, &goal);
             dist_a.cmp(&dist_b)
@@ -231,42 +317,58 @@ impl Database {
         return min_node.ok_or(anyhow!("No node near {:?} {:?}", lat, lon));
     }
 
-    pub fn neighbours(&self, node: &RouteNode) -> Vec<(RouteNode, i32)> {
-        self.neighbours_res(node).unwrap_or(Vec::new())
+    pub fn neighbours(&self, node: &RouteNode, mode: RouteMode) -> Vec<(RouteNode, i32)> {
+        self.neighbours_res(node, mode).unwrap_or(Vec::new())
     }
 
-    fn neighbours_res(&self, node: &RouteNode) -> Result<Vec<(RouteNode, i32)>> {
-        let mut stmt_src = self.conn.prepare(
-            "SELECT n.id, n.lat, n.lon FROM links l
-        left join nodes n on l.destination = n.id
-        where l.source = ?",
-        )?;
-        let mut stmt_dst = self.conn.prepare(
-            "SELECT n.id, n.lat, n.lon FROM links l
-        left join nodes n on l.source = n.id
-        where l.destination = ?",
-        )?;
+    /// Waterways are directed: a way's node order follows the direction the
+    /// water flows. `out_edges` follows that direction (downstream),
+    /// `in_edges` follows it against the grain (upstream), and `both` unions
+    /// the two, ignoring flow direction entirely.
+    fn neighbours_res(&self, node: &RouteNode, mode: RouteMode) -> Result<Vec<(RouteNode, i32)>> {
+        let conn = self.pool.get()?;
+
+        // A plain join, not a left join: a link whose other endpoint was
+        // never inserted (a boundary artifact of a clipped .osm.pbf extract)
+        // must disappear as one missing edge, not surface as a NULL node row
+        // that fails row_extract and takes the whole neighbour list down
+        // with it via the `?` below.
+        let out_edges = "SELECT n.id, n.lat, n.lon FROM links l
+        join nodes n on l.destination = n.id
+        where l.source = ?";
+        let in_edges = "SELECT n.id, n.lat, n.lon FROM links l
+        join nodes n on l.source = n.id
+        where l.destination = ?";
+
+        let nodes: Vec<RouteNode> = match mode {
+            RouteMode::Downstream => {
+                let mut stmt = conn.prepare(out_edges)?;
+                stmt.query_map(params![node.id], row_extract::<RouteNode>)?
+                    .collect::<rusqlite::Result<Vec<_>>>()?
+            }
+            RouteMode::Upstream => {
+                let mut stmt = conn.prepare(in_edges)?;
+                stmt.query_map(params![node.id], row_extract::<RouteNode>)?
+                    .collect::<rusqlite::Result<Vec<_>>>()?
+            }
+            RouteMode::Both => {
+                let mut stmt_src = conn.prepare(out_edges)?;
+                let mut stmt_dst = conn.prepare(in_edges)?;
+                stmt_src
+                    .query_map(params![node.id], row_extract::<RouteNode>)?
+                    .chain(stmt_dst.query_map(params![node.id], row_extract::<RouteNode>)?)
+                    .collect::<rusqlite::Result<Vec<_>>>()?
+            }
+        };
 
-        let nodes_iter = stmt_src
-            .query_map(params![node.id], |row| {
-                Ok(RouteNode {
-                    id: row.get(0)?,
-                    lat: row.get(1)?,
-                    lon: row.get(2)?,
-                })
-            })?
-            .chain(stmt_dst.query_map(params![node.id], |row| {
-                Ok(RouteNode {
-                    id: row.get(0)?,
-                    lat: row.get(1)?,
-                    lon: row.get(2)?,
-                })
-            })?)
-            .filter(|n| n.is_ok())
-            .map(|n| n.unwrap())
-            .map(|n| (n.clone(), distance_between(&node, &n)))
+        let neighbours = nodes
+            .into_iter()
+            .map(|n| {
+                let distance = distance_between(&node, &n);
+                (n, distance)
+            })
             .collect();
-        return Ok(nodes_iter);
+        return Ok(neighbours);
     }
 }
 
@@ -0,0 +1,59 @@
+use lazy_static::lazy_static;
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntGauge, Registry, TextEncoder};
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+    pub static ref ROUTE_REQUESTS_TOTAL: IntCounter =
+        IntCounter::new("route_requests_total", "Total number of /route requests").unwrap();
+    pub static ref ROUTE_NODE_NOT_FOUND_TOTAL: IntCounter = IntCounter::new(
+        "route_node_not_found_total",
+        "Total number of /route requests rejected because a node could not be found"
+    )
+    .unwrap();
+    pub static ref ROUTE_NO_PATH_FOUND_TOTAL: IntCounter = IntCounter::new(
+        "route_no_path_found_total",
+        "Total number of /route requests where start and goal were valid nodes but astar found no connecting path"
+    )
+    .unwrap();
+    pub static ref ASTAR_SEARCH_DURATION_SECONDS: Histogram = Histogram::with_opts(HistogramOpts::new(
+        "astar_search_duration_seconds",
+        "Duration of the A* route search in seconds"
+    ))
+    .unwrap();
+    pub static ref GRAPH_NODE_COUNT: IntGauge =
+        IntGauge::new("graph_node_count", "Number of nodes in the routing graph").unwrap();
+    pub static ref GRAPH_LINK_COUNT: IntGauge =
+        IntGauge::new("graph_link_count", "Number of links in the routing graph").unwrap();
+}
+
+/// Registers all metrics with the process-wide registry. Must be called once
+/// before the first `/metrics` scrape.
+pub fn register() {
+    REGISTRY
+        .register(Box::new(ROUTE_REQUESTS_TOTAL.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(ROUTE_NODE_NOT_FOUND_TOTAL.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(ROUTE_NO_PATH_FOUND_TOTAL.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(ASTAR_SEARCH_DURATION_SECONDS.clone()))
+        .unwrap();
+    REGISTRY.register(Box::new(GRAPH_NODE_COUNT.clone())).unwrap();
+    REGISTRY.register(Box::new(GRAPH_LINK_COUNT.clone())).unwrap();
+}
+
+pub fn update_graph_gauges(node_count: i64, link_count: i64) {
+    GRAPH_NODE_COUNT.set(node_count);
+    GRAPH_LINK_COUNT.set(link_count);
+}
+
+/// Renders all registered metrics in Prometheus text exposition format.
+pub fn render() -> anyhow::Result<String> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder.encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
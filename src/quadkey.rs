@@ -6,6 +6,12 @@ pub struct Quadkey(String);
 impl Quadkey {
     pub fn new(lat: f64, lon: f64, zoom: u8) -> Self {
         let (xtile, ytile) = Self::tile(lat, lon, zoom);
+        Self::from_tile(xtile, ytile, zoom)
+    }
+
+    /// Builds a quadkey directly from tile coordinates, e.g. a neighbouring
+    /// tile obtained by offsetting the result of `tile()`.
+    pub fn from_tile(xtile: u32, ytile: u32, zoom: u8) -> Self {
         let mut quad_key = String::new();
         for i in (1..=zoom).rev() {
             let mut digit: u8 = 0;
@@ -32,7 +38,7 @@ impl Quadkey {
         &self.0
     }
 
-    fn tile(lat: f64, lon: f64, zoom: u8) -> (u32, u32) {
+    pub fn tile(lat: f64, lon: f64, zoom: u8) -> (u32, u32) {
         let lat_rad = lat * PI / 180.0;
         let n = (2.0 as f64).powi(zoom as i32);
         let xtile = ((lon + 180.0) / 360.0 * n).round() as u32;
@@ -50,4 +56,26 @@ mod tests {
     fn test_foo() {
         assert_eq!(Quadkey::new(0.0, 0.0, 4).to_string(), &"3000".to_string());
     }
+
+    #[test]
+    fn test_tile_wraps_at_antimeridian() {
+        let zoom = 6;
+        let tiles_per_axis = 1i64 << zoom;
+        let (xtile, _) = Quadkey::tile(0.0, 175.0, zoom);
+        assert_eq!(xtile, (tiles_per_axis - 1) as u32);
+        // the neighbouring tile east of the last column wraps back to column 0,
+        // the same way find_near_err's 3x3 block offsets tiles
+        let neighbour = (xtile as i64 + 1).rem_euclid(tiles_per_axis) as u32;
+        assert_eq!(neighbour, 0);
+    }
+
+    #[test]
+    fn test_tile_clamped_near_pole() {
+        let zoom = 6;
+        let (_, ytile) = Quadkey::tile(85.0, 0.0, zoom);
+        // row 0 is the northernmost tile; there is no row "above" it to offset
+        // into, so callers must skip rather than wrap that offset
+        assert_eq!(ytile, 0);
+        assert!(ytile as i64 - 1 < 0);
+    }
 }
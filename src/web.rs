@@ -2,14 +2,13 @@ use log::info;
 use pathfinding::prelude::astar;
 use serde::{Deserialize, Serialize};
 use std::convert::Infallible;
-use std::sync::Arc;
-use tokio::sync::Mutex;
 use warp::http::StatusCode;
 use warp::{reject, Filter, Rejection, Reply};
 
-use crate::db::Database;
+use crate::db::{Database, RouteMode};
+use crate::metrics;
 
-type DatabasePool = Arc<Mutex<Database>>;
+type DatabasePool = Database;
 
 #[derive(Deserialize)]
 struct QueryParams {
@@ -17,19 +16,29 @@ struct QueryParams {
     lon1: f32,
     lat2: f32,
     lon2: f32,
+    format: Option<String>,
+    #[serde(default)]
+    mode: RouteMode,
 }
 
 pub async fn serve(database: Database) -> () {
-    let dbpool = Arc::new(Mutex::new(database));
-    let env = warp::any().map(move || dbpool.clone());
+    metrics::register();
+
+    let env = warp::any().map(move || database.clone());
 
     let route = warp::get()
         .and(warp::path("route"))
         .and(warp::query::<QueryParams>())
+        .and(warp::header::optional::<String>("accept"))
         .and(env.clone())
         .and_then(route);
 
-    let routes = route.recover(handle_rejection);
+    let metrics_route = warp::get()
+        .and(warp::path("metrics"))
+        .and(env.clone())
+        .and_then(metrics_handler);
+
+    let routes = route.or(metrics_route).recover(handle_rejection);
 
     let server = warp::serve(routes);
     let s = server.run(([127, 0, 0, 1], 8081));
@@ -50,6 +59,34 @@ struct RouteResult {
     distance: i32,
 }
 
+/// A GeoJSON `Feature` wrapping a route's `LineString` geometry, for clients
+/// that want to drop the response straight into a mapping library. Geometry
+/// coordinates follow the GeoJSON `[lon, lat]` axis order.
+#[derive(Serialize)]
+struct GeoJsonFeature {
+    #[serde(rename = "type")]
+    feature_type: &'static str,
+    geometry: GeoJsonGeometry,
+    properties: GeoJsonProperties,
+}
+
+#[derive(Serialize)]
+struct GeoJsonGeometry {
+    #[serde(rename = "type")]
+    geometry_type: &'static str,
+    coordinates: Vec<(f64, f64)>,
+}
+
+#[derive(Serialize)]
+struct GeoJsonProperties {
+    distance: i32,
+}
+
+fn wants_geojson(params: &QueryParams, accept: &Option<String>) -> bool {
+    params.format.as_deref() == Some("geojson")
+        || accept.as_deref() == Some("application/geo+json")
+}
+
 #[derive(Debug)]
 struct NodeNotFound;
 
@@ -77,36 +114,71 @@ async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
     Ok(warp::reply::with_status(json, code))
 }
 
+async fn metrics_handler(
+    database: DatabasePool,
+) -> std::result::Result<impl Reply, Rejection> {
+    if let (Ok(nodes), Ok(links)) = (database.node_count(), database.link_count()) {
+        metrics::update_graph_gauges(nodes, links);
+    }
+
+    match metrics::render() {
+        Ok(body) => Ok(warp::reply::with_header(
+            body,
+            "Content-Type",
+            "text/plain; version=0.0.4",
+        )),
+        Err(_) => Err(reject()),
+    }
+}
+
 async fn route(
     params: QueryParams,
-    db_pool: DatabasePool,
+    accept: Option<String>,
+    database: DatabasePool,
 ) -> std::result::Result<impl Reply, Rejection> {
-    let database = db_pool.lock().await;
+    metrics::ROUTE_REQUESTS_TOTAL.inc();
+
     let maybe_start = database.find_near(params.lat1 as f64, params.lon1 as f64);
     let maybe_goal = database.find_near(params.lat2 as f64, params.lon2 as f64);
 
     if maybe_start.is_none() || maybe_goal.is_none() {
+        metrics::ROUTE_NODE_NOT_FOUND_TOTAL.inc();
         return Err(reject::custom(NodeNotFound));
     }
 
     let start = maybe_start.unwrap();
     let goal = maybe_goal.unwrap();
 
+    let timer = metrics::ASTAR_SEARCH_DURATION_SECONDS.start_timer();
     let result = astar(
         &start,
-        |n| database.neighbours(n),
+        |n| database.neighbours(n, params.mode),
         |n| n.distance_to(&goal),
         |n| *n == goal || n.distance_to(&goal) < 100,
     );
+    timer.observe_duration();
 
     if let Some((nodes, distance)) = result {
-        let linestring: Vec<(f64, f64)> = nodes.iter().map(|n| (n.lat, n.lon)).collect();
-        let json = warp::reply::json(&RouteResult {
-            linestring: linestring,
-            distance: distance,
-        });
+        let json = if wants_geojson(&params, &accept) {
+            let coordinates: Vec<(f64, f64)> = nodes.iter().map(|n| (n.lon, n.lat)).collect();
+            warp::reply::json(&GeoJsonFeature {
+                feature_type: "Feature",
+                geometry: GeoJsonGeometry {
+                    geometry_type: "LineString",
+                    coordinates: coordinates,
+                },
+                properties: GeoJsonProperties { distance: distance },
+            })
+        } else {
+            let linestring: Vec<(f64, f64)> = nodes.iter().map(|n| (n.lat, n.lon)).collect();
+            warp::reply::json(&RouteResult {
+                linestring: linestring,
+                distance: distance,
+            })
+        };
         Ok(warp::reply::with_status(json, StatusCode::OK))
     } else {
+        metrics::ROUTE_NO_PATH_FOUND_TOTAL.inc();
         return Err(reject());
     }
 }